@@ -6,15 +6,16 @@ use cli_table::{format::Justify, print_stdout, Table, WithTitle};
 use console::Term;
 use hdrhistogram::Histogram;
 use indicatif::ProgressBar;
+use serde::Serialize;
 use std::{
-    collections::HashMap,
     net::IpAddr,
     num::NonZeroUsize,
     path::PathBuf,
+    sync::{mpsc, Condvar, Mutex},
     time::{Duration, Instant},
 };
-use trust_dns_client::rr::Name;
-use utils::{parse_dns_addrs, resolve};
+use trust_dns_client::rr::{Name, RecordType};
+use utils::{parse_dns_addrs, parse_resolv_conf, resolve, ServerStats, Transport};
 
 /// Simple program to benchmark DNS servers
 #[derive(Parser, Debug)]
@@ -29,112 +30,352 @@ struct Args {
     attempts: NonZeroUsize,
 
     /// File containing newline delimited DNS addresses to measure
+    /// (defaults to the nameservers in /etc/resolv.conf when omitted)
     #[clap(short, long)]
-    file: PathBuf,
+    file: Option<PathBuf>,
+
+    /// Record type to query (A, AAAA, MX, TXT, NS, SOA, CNAME, PTR, SRV)
+    #[clap(short = 't', long, default_value = "A")]
+    record_type: RecordType,
 
     /// Rate limited delay between each query of the same DNS server in seconds
     #[clap(short, long, default_value = "5")]
     rate_limit: u64,
+
+    /// Minimum adaptive query timeout in milliseconds
+    #[clap(long, default_value = "10")]
+    min_timeout: u64,
+
+    /// Maximum adaptive query timeout in milliseconds
+    #[clap(long, default_value = "5000")]
+    max_timeout: u64,
+
+    /// Transport protocol to benchmark
+    #[clap(long, value_enum, default_value_t = Transport::Udp)]
+    transport: Transport,
+
+    /// Path to POST to when using the DoH transport
+    #[clap(long, default_value = "/dns-query")]
+    doh_path: String,
+
+    /// Maximum number of queries in flight at once across all servers
+    #[clap(short, long, default_value = "8")]
+    concurrency: NonZeroUsize,
+
+    /// Total budget in seconds for UDP retransmissions before declaring failure
+    #[clap(long, default_value = "10")]
+    retransmit_timeout: u64,
+
+    /// Output format for the results
+    #[clap(short, long, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+}
+
+/// How to render the benchmark results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Table,
+    Json,
+    Csv,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let dns_servers = parse_dns_addrs(args.file)?;
+    let dns_servers = match &args.file {
+        Some(file) => parse_dns_addrs(file)?,
+        None => parse_resolv_conf("/etc/resolv.conf")?,
+    };
     let attempts = usize::from(args.attempts);
     let total_requests = dns_servers.len() * attempts;
     let mut results = Vec::with_capacity(total_requests);
-    let mut last_start_times = HashMap::new();
+    let min_timeout = Duration::from_millis(args.min_timeout);
+    let max_timeout = Duration::from_millis(args.max_timeout);
     let rate_limit = Duration::from_secs(args.rate_limit);
-    let term = Term::stdout();
+    let retransmit_timeout = Duration::from_secs(args.retransmit_timeout);
+    // Status and progress go to stderr so that stdout carries only the results,
+    // keeping the JSON/CSV output pipeable.
+    let term = Term::stderr();
     term.write_line("Benchmarking...")?;
     let progress_bar = ProgressBar::new(total_requests.try_into()?);
     let start_time = Instant::now();
-    for _ in 0..attempts {
+    // Each server is an independent, rate-limited stream of queries running on
+    // its own thread; a semaphore bounds the number of queries in flight across
+    // all of them, and finished results flow back over a channel.
+    let semaphore = Semaphore::new(usize::from(args.concurrency));
+    let (tx, rx) = mpsc::channel();
+    let domain_name = &args.domain_name;
+    let doh_path = args.doh_path.as_str();
+    std::thread::scope(|scope| {
         for dns_server in &dns_servers {
-            if let Some(previous_time) = last_start_times.get(dns_server) {
-                let duration_from_previous_run = Instant::now().duration_since(*previous_time);
-                let time_to_wait = rate_limit
-                    .checked_sub(duration_from_previous_run)
-                    .unwrap_or_default();
-                std::thread::sleep(time_to_wait);
-            }
-            let elapsed = resolve(args.domain_name.clone(), dns_server.clone());
-            let ended = Instant::now();
-            let result = match elapsed {
-                Ok(d) => ResultState::Success(d),
-                Err(_) => ResultState::Failed,
-            };
-            let result = BenchResult { dns_server, result };
-            last_start_times.insert(dns_server, ended);
+            let tx = tx.clone();
+            let semaphore = &semaphore;
+            scope.spawn(move || {
+                let mut stats = ServerStats::new(min_timeout, max_timeout);
+                let mut last_start: Option<Instant> = None;
+                for _ in 0..attempts {
+                    if let Some(previous_time) = last_start {
+                        let time_to_wait = rate_limit
+                            .checked_sub(previous_time.elapsed())
+                            .unwrap_or_default();
+                        std::thread::sleep(time_to_wait);
+                    }
+                    let permit = semaphore.acquire();
+                    last_start = Some(Instant::now());
+                    let outcome = resolve(
+                        domain_name.clone(),
+                        *dns_server,
+                        args.record_type,
+                        &mut stats,
+                        args.transport,
+                        doh_path,
+                        retransmit_timeout,
+                    );
+                    drop(permit);
+                    let result = match outcome {
+                        Ok(o) if o.retransmitted => ResultState::Retransmitted(o.elapsed),
+                        Ok(o) => ResultState::Success(o.elapsed),
+                        Err(_) => ResultState::Failed,
+                    };
+                    let _ = tx.send(BenchResult {
+                        dns_server: *dns_server,
+                        result,
+                    });
+                }
+            });
+        }
+        // Drop the original sender so the receiver terminates once every
+        // worker thread has finished and dropped its clone.
+        drop(tx);
+        for result in rx {
             progress_bar.inc(1);
             results.push(result);
         }
-    }
+    });
     let total_time_taken = start_time.elapsed();
     progress_bar.finish_and_clear();
     term.clear_last_lines(2)?;
     term.write_line(&format!("Total time taken: {:?}", total_time_taken))?;
     let mut dns_results = Vec::with_capacity(dns_servers.len());
     for dns_server in &dns_servers {
-        let filter_by_dns = results.iter().filter(|r| r.dns_server == dns_server);
+        let filter_by_dns = results.iter().filter(|r| r.dns_server == *dns_server);
         let failed_requests = filter_by_dns
             .clone()
             .filter(|r| r.result == ResultState::Failed)
             .count();
+        let retransmitted_requests = filter_by_dns
+            .clone()
+            .filter(|r| matches!(r.result, ResultState::Retransmitted(_)))
+            .count();
         let mut hist = Histogram::<u64>::new(3).unwrap();
+        let mut samples = Vec::new();
         for res in filter_by_dns {
-            if let ResultState::Success(duration) = res.result {
-                hist.record(duration.as_millis().try_into()?)?;
+            if let Some(duration) = res.result.duration() {
+                let millis = duration.as_millis().try_into()?;
+                hist.record(millis)?;
+                samples.push(millis);
             }
         }
         let result = DnsResult {
             dns: dns_server,
             failed: failed_requests,
+            retransmitted: retransmitted_requests,
             hist,
+            samples,
         };
         dns_results.push(result);
     }
     dns_results.sort_unstable_by(|a, b| a.hist.mean().partial_cmp(&b.hist.mean()).unwrap());
-    term.write_line("DNS servers are ordered from best to worst by its mean request, but it's best to look at the data and rank the servers yourself.")?;
-    term.write_line("")?;
-    render_result(dns_results, attempts)?;
+    match args.output {
+        OutputFormat::Table => {
+            term.write_line("DNS servers are ordered from best to worst by its mean request, but it's best to look at the data and rank the servers yourself.")?;
+            term.write_line("")?;
+            render_table(&dns_results, attempts)?;
+        }
+        OutputFormat::Json => render_json(&dns_results, attempts, args.record_type, total_time_taken)?,
+        OutputFormat::Csv => render_csv(&dns_results, attempts)?,
+    }
     Ok(())
 }
 
-fn render_result(dns_results: Vec<DnsResult>, attempts: usize) -> Result<()> {
-    let data: Vec<_> = dns_results
-        .into_iter()
-        .map(|dns_result| TableResult {
-            dns: *dns_result.dns,
-            requests: attempts,
-            errors: dns_result.failed,
-            min: dns_result.hist.min(),
-            p50: dns_result.hist.value_at_percentile(50.0),
-            p95: dns_result.hist.value_at_percentile(95.0),
-            p99: dns_result.hist.value_at_percentile(99.0),
-            p999: dns_result.hist.value_at_percentile(99.9),
-            max: dns_result.hist.max(),
-        })
-        .collect();
+fn render_table(dns_results: &[DnsResult], attempts: usize) -> Result<()> {
+    let data: Vec<_> = dns_results.iter().map(|r| r.as_row(attempts)).collect();
     print_stdout(data.with_title())?;
     Ok(())
 }
 
-struct BenchResult<'a> {
-    dns_server: &'a IpAddr,
+fn render_json(
+    dns_results: &[DnsResult],
+    attempts: usize,
+    record_type: RecordType,
+    total_time_taken: Duration,
+) -> Result<()> {
+    let report = BenchmarkReport {
+        total_time_taken_ms: total_time_taken.as_millis(),
+        record_type: record_type.to_string(),
+        attempts,
+        servers: dns_results
+            .iter()
+            .map(|r| r.as_report(attempts))
+            .collect(),
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+fn render_csv(dns_results: &[DnsResult], attempts: usize) -> Result<()> {
+    println!("dns,requests,errors,retransmits,min,p50,p95,p99,p999,max,samples");
+    for r in dns_results {
+        let report = r.as_report(attempts);
+        let samples = report
+            .samples
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!(
+            "{},{},{},{},{},{},{},{},{},{},{}",
+            report.dns,
+            report.requests,
+            report.errors,
+            report.retransmits,
+            report.min,
+            report.p50,
+            report.p95,
+            report.p99,
+            report.p999,
+            report.max,
+            samples,
+        );
+    }
+    Ok(())
+}
+
+struct BenchResult {
+    dns_server: IpAddr,
     result: ResultState,
 }
 
+/// A counting semaphore that bounds how many queries may be in flight at once.
+/// `acquire` blocks until a permit is free and returns a guard that releases
+/// the permit when dropped.
+struct Semaphore {
+    permits: Mutex<usize>,
+    available: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Self {
+            permits: Mutex::new(permits),
+            available: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> SemaphorePermit<'_> {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.available.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphorePermit { semaphore: self }
+    }
+}
+
+struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.permits.lock().unwrap() += 1;
+        self.semaphore.available.notify_one();
+    }
+}
+
 #[derive(PartialEq, Eq)]
 enum ResultState {
+    /// Succeeded without any retransmit (the first UDP packet, or a TCP, DoT
+    /// or DoH query).
     Success(Duration),
+    /// Succeeded, but only after one or more retransmits (packet loss).
+    Retransmitted(Duration),
     Failed,
 }
 
+impl ResultState {
+    /// Latency of a successful query, regardless of whether it needed a
+    /// retransmit.
+    fn duration(&self) -> Option<Duration> {
+        match self {
+            ResultState::Success(d) | ResultState::Retransmitted(d) => Some(*d),
+            ResultState::Failed => None,
+        }
+    }
+}
+
 struct DnsResult<'a> {
     dns: &'a IpAddr,
     failed: usize,
+    retransmitted: usize,
     hist: Histogram<u64>,
+    samples: Vec<u64>,
+}
+
+impl DnsResult<'_> {
+    fn as_row(&self, attempts: usize) -> TableResult {
+        TableResult {
+            dns: *self.dns,
+            requests: attempts,
+            errors: self.failed,
+            retransmits: self.retransmitted,
+            min: self.hist.min(),
+            p50: self.hist.value_at_percentile(50.0),
+            p95: self.hist.value_at_percentile(95.0),
+            p99: self.hist.value_at_percentile(99.0),
+            p999: self.hist.value_at_percentile(99.9),
+            max: self.hist.max(),
+        }
+    }
+
+    fn as_report(&self, attempts: usize) -> ServerReport {
+        ServerReport {
+            dns: *self.dns,
+            requests: attempts,
+            errors: self.failed,
+            retransmits: self.retransmitted,
+            min: self.hist.min(),
+            p50: self.hist.value_at_percentile(50.0),
+            p95: self.hist.value_at_percentile(95.0),
+            p99: self.hist.value_at_percentile(99.0),
+            p999: self.hist.value_at_percentile(99.9),
+            max: self.hist.max(),
+            samples: self.samples.clone(),
+        }
+    }
+}
+
+/// Top-level serializable view of a benchmark run for JSON output.
+#[derive(Serialize)]
+struct BenchmarkReport {
+    total_time_taken_ms: u128,
+    record_type: String,
+    attempts: usize,
+    servers: Vec<ServerReport>,
+}
+
+#[derive(Serialize)]
+struct ServerReport {
+    dns: IpAddr,
+    requests: usize,
+    errors: usize,
+    retransmits: usize,
+    min: u64,
+    p50: u64,
+    p95: u64,
+    p99: u64,
+    p999: u64,
+    max: u64,
+    samples: Vec<u64>,
 }
 
 #[derive(Table)]
@@ -148,6 +389,9 @@ struct TableResult {
     #[table(title = "Errors", justify = "Justify::Right")]
     errors: usize,
 
+    #[table(title = "Retransmits", justify = "Justify::Right")]
+    retransmits: usize,
+
     #[table(title = "Min (ms)", justify = "Justify::Right")]
     min: u64,
 